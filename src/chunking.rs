@@ -0,0 +1,40 @@
+//! Splits long documents into overlapping token-sized windows.
+//!
+//! Used by `process_document`'s map-reduce path when a document does not fit
+//! in the model's context window in one shot.
+
+/// The closest valid char boundary at or before `idx`.
+fn char_boundary(s: &str, mut idx: usize) -> usize {
+    idx = idx.min(s.len());
+    while !s.is_char_boundary(idx) {
+        idx -= 1;
+    }
+    idx
+}
+
+/// Split `content` into windows of at most `tokens_per_window` tokens
+/// (estimated via `chars_per_token`), with `overlap_tokens` tokens of overlap
+/// between consecutive windows so that figures straddling a window boundary
+/// are not lost.
+pub fn windows(
+    content: &str,
+    tokens_per_window: usize,
+    overlap_tokens: usize,
+    chars_per_token: f32,
+) -> Vec<&str> {
+    let window_bytes = ((tokens_per_window as f32) * chars_per_token).round().max(1.0) as usize;
+    let overlap_bytes = ((overlap_tokens as f32) * chars_per_token).round() as usize;
+    let step = window_bytes.saturating_sub(overlap_bytes).max(1);
+
+    let mut out = Vec::new();
+    let mut start = 0;
+    while start < content.len() {
+        let end = char_boundary(content, start + window_bytes);
+        out.push(&content[start..end]);
+        if end >= content.len() {
+            break;
+        }
+        start = char_boundary(content, start + step);
+    }
+    out
+}
@@ -0,0 +1,248 @@
+//! Declarative description of extraction tasks, loaded from a TOML config file.
+//!
+//! This replaces the crate's former hardcoded title+amount workflow: each
+//! [`Task`] describes its own inbox tag, prompt, and the fields to extract from
+//! the document, each with a [`FieldType`] (used to generate the grammar/schema
+//! sent to the backend and to parse its response) and a [`FieldTarget`] (where
+//! the extracted value is written back to in paperless).
+use std::path::Path;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Config {
+    pub tasks: Vec<Task>,
+}
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&data)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+}
+
+/// A single extraction workflow: documents tagged `tag` are sent through
+/// `prompt`, and the extracted `fields` are applied back to the document.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Task {
+    /// Inbox tag identifying documents to process with this task.
+    pub tag: String,
+    /// System prompt sent to the model. May reference `CURRENCY`, substituted
+    /// at runtime from `--currency`.
+    pub prompt: String,
+    pub fields: Vec<Field>,
+    /// Embedding-based classification, assigning the document to the closest
+    /// existing correspondent and/or one of `tags`, as an alternative to
+    /// extracting them via the chat model. Requires `--embeddings-url`.
+    #[serde(default)]
+    pub classify: Option<Classify>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Classify {
+    /// Assign the closest existing correspondent.
+    #[serde(default)]
+    pub correspondent: bool,
+    /// Assign the closest tag among this set.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Field {
+    /// Name used in logs and diff messages.
+    pub name: String,
+    #[serde(rename = "type")]
+    pub r#type: FieldType,
+    pub target: FieldTarget,
+    /// Whether the model may omit the field (encoded as `null` in its output).
+    #[serde(default)]
+    pub optional: bool,
+    /// For `number` fields written to a [`FieldTarget::CustomField`], format
+    /// the value as `{currency}{amount:.2}` (e.g. `CHF12.00`) instead of a
+    /// bare JSON number, matching paperless' monetary custom field format.
+    #[serde(default)]
+    pub currency: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldType {
+    String,
+    Number,
+    /// `YYYY-MM-DD`.
+    Date,
+    /// One of a fixed set of values, typically matching existing paperless tag
+    /// names.
+    Enum(Vec<String>),
+}
+
+/// Where an extracted field value is written back to in paperless.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldTarget {
+    Title,
+    /// Assigns the correspondent named after the extracted value (meant for
+    /// `Enum` fields, listing existing correspondent names).
+    Correspondent,
+    /// Assigns the document type named after the extracted value (meant for
+    /// `Enum` fields, listing existing document type names).
+    DocumentType,
+    /// Assigns a tag named after the extracted value (meant for `Enum` fields).
+    Tag,
+    CustomField(String),
+}
+
+#[derive(Debug, Clone)]
+pub enum FieldValue {
+    String(String),
+    Number(f64),
+    Date(chrono::NaiveDate),
+}
+
+/// Build a JSON schema describing an object with one property per field,
+/// suitable for a backend's `response_format: json_schema`.
+pub fn build_json_schema(fields: &[Field]) -> serde_json::Value {
+    let mut properties = serde_json::Map::new();
+    let mut required = Vec::new();
+    for field in fields {
+        let mut schema = match &field.r#type {
+            FieldType::String => serde_json::json!({"type": "string"}),
+            FieldType::Number => serde_json::json!({"type": "number"}),
+            FieldType::Date => serde_json::json!({
+                "type": "string",
+                "pattern": r"^\d{4}-\d{2}-\d{2}$",
+            }),
+            FieldType::Enum(values) => serde_json::json!({"type": "string", "enum": values}),
+        };
+        if field.optional {
+            let ty = schema["type"].as_str().unwrap_or("string").to_string();
+            schema["type"] = serde_json::json!([ty, "null"]);
+            // `enum` is a separate constraint from `type`: under strict
+            // JSON-schema semantics, null satisfying `type` does not make it
+            // a member of `enum`, so it must be added explicitly or absence
+            // becomes unrepresentable.
+            if let Some(values) = schema.get_mut("enum").and_then(|v| v.as_array_mut()) {
+                values.push(serde_json::Value::Null);
+            }
+        } else {
+            required.push(field.name.clone());
+        }
+        properties.insert(field.name.clone(), schema);
+    }
+    serde_json::json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
+
+/// Build a GBNF grammar constraining the completion to a JSON object of the
+/// same shape [`parse_output`] expects from the JSON-schema path (one key per
+/// field, in order), so the same parser handles both. Kept as a fallback for
+/// backends that only support GBNF grammars rather than `response_format:
+/// json_schema`.
+pub fn build_grammar(fields: &[Field]) -> String {
+    let mut rules = Vec::new();
+    let mut entries = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        let rule_name = format!("field{i}");
+        let mut body = match &field.r#type {
+            FieldType::String => r#""\"" [^"\\]* "\"""#.to_string(),
+            FieldType::Number => r#""-"? [0-9]+ ("." [0-9]+)?"#.to_string(),
+            FieldType::Date => {
+                r#""\"" [0-9] [0-9] [0-9] [0-9] "-" [0-9] [0-9] "-" [0-9] [0-9] "\"""#.to_string()
+            }
+            FieldType::Enum(values) => values
+                .iter()
+                .map(|v| format!(r#""\"{v}\"""#))
+                .collect::<Vec<_>>()
+                .join(" | "),
+        };
+        if field.optional {
+            body = format!("({body}) | \"null\"");
+        }
+        rules.push(format!("{rule_name} ::= {body}"));
+        entries.push(format!(r#""\"{}\":" {rule_name}"#, field.name));
+    }
+    let root = format!(r#"root ::= "{{" {} "}}""#, entries.join(r#" "," "#));
+    std::iter::once(root)
+        .chain(rules)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Inverse of [`parse_output`]: serialize a parsed result back to a JSON
+/// object, e.g. to feed partial results from one extraction into another.
+pub fn to_json(output: &[(Field, Option<FieldValue>)]) -> serde_json::Value {
+    let mut map = serde_json::Map::new();
+    for (field, value) in output {
+        let value = match value {
+            None => serde_json::Value::Null,
+            Some(FieldValue::String(s)) => serde_json::Value::String(s.clone()),
+            Some(FieldValue::Number(n)) => serde_json::json!(n),
+            Some(FieldValue::Date(d)) => serde_json::Value::String(d.format("%Y-%m-%d").to_string()),
+        };
+        map.insert(field.name.clone(), value);
+    }
+    serde_json::Value::Object(map)
+}
+
+/// Parse a JSON object produced by the model, validating each field against
+/// its declared [`FieldType`].
+pub fn parse_output(
+    fields: &[Field],
+    text: &str,
+) -> anyhow::Result<Vec<(Field, Option<FieldValue>)>> {
+    let obj: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(text).context("Response is not a valid JSON object")?;
+    fields
+        .iter()
+        .map(|field| {
+            let raw = match obj.get(&field.name) {
+                None | Some(serde_json::Value::Null) => {
+                    anyhow::ensure!(field.optional, "Field '{}' is missing", field.name);
+                    return Ok((field.clone(), None));
+                }
+                Some(v) => v,
+            };
+            let value = match &field.r#type {
+                FieldType::String => FieldValue::String(
+                    raw.as_str()
+                        .with_context(|| format!("Field '{}': expected a string", field.name))?
+                        .into(),
+                ),
+                FieldType::Number => FieldValue::Number(raw.as_f64().with_context(|| {
+                    format!("Field '{}': expected a number", field.name)
+                })?),
+                FieldType::Date => {
+                    let s = raw
+                        .as_str()
+                        .with_context(|| format!("Field '{}': expected a string", field.name))?;
+                    FieldValue::Date(
+                        chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d").with_context(|| {
+                            format!("Field '{}': not a date (YYYY-MM-DD)", field.name)
+                        })?,
+                    )
+                }
+                FieldType::Enum(values) => {
+                    let s = raw
+                        .as_str()
+                        .with_context(|| format!("Field '{}': expected a string", field.name))?;
+                    anyhow::ensure!(
+                        values.iter().any(|v| v == s),
+                        "Field '{}': '{}' is not one of {:?}",
+                        field.name,
+                        s,
+                        values
+                    );
+                    FieldValue::String(s.into())
+                }
+            };
+            Ok((field.clone(), Some(value)))
+        })
+        .collect()
+}
@@ -0,0 +1,70 @@
+//! Embedding-based classification of documents against existing paperless
+//! correspondents/tags, as an alternative to asking the chat model to pick
+//! from a (possibly very large) list of candidates in the prompt.
+//!
+//! Only the llama.cpp backend is supported for now, via its `/embedding`
+//! endpoint.
+use std::collections::HashMap;
+
+/// A candidate label (a correspondent or tag name) together with its cached
+/// embedding vector.
+struct Label {
+    name: String,
+    id: usize,
+    vector: Vec<f32>,
+}
+
+/// Assigns documents to the closest of a fixed set of labels.
+pub struct Classifier {
+    labels: Vec<Label>,
+}
+impl Classifier {
+    /// Embed `names` (e.g. all existing correspondents, or a subset of tags)
+    /// and cache the resulting vectors.
+    pub async fn build(
+        llamacpp: &crate::llamacpp::LlamaCpp,
+        names: &HashMap<String, usize>,
+    ) -> anyhow::Result<Self> {
+        let mut labels = Vec::with_capacity(names.len());
+        for (name, &id) in names {
+            let vector = llamacpp.embedding(name).await?;
+            labels.push(Label {
+                name: name.clone(),
+                id,
+                vector,
+            });
+        }
+        Ok(Self { labels })
+    }
+
+    /// The label (name and paperless id) closest to `content`, if its cosine
+    /// similarity is at least `min_similarity`. `content` must already fit in
+    /// the embedding model's context window; callers are responsible for
+    /// truncating it first.
+    pub async fn classify(
+        &self,
+        llamacpp: &crate::llamacpp::LlamaCpp,
+        content: &str,
+        min_similarity: f32,
+    ) -> anyhow::Result<Option<(String, usize)>> {
+        let vector = llamacpp.embedding(content).await?;
+        Ok(self
+            .labels
+            .iter()
+            .map(|label| (label, cosine_similarity(&label.vector, &vector)))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .filter(|(_, similarity)| *similarity >= min_similarity)
+            .map(|(label, _)| (label.name.clone(), label.id)))
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
@@ -5,76 +5,92 @@ use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use tracing::*;
 
+use crate::llm::{self, LlmBackend};
+
 #[derive(Clone)]
 pub struct LlamaCpp {
     client: reqwest::Client,
     url: reqwest::Url,
-    pub settings: GenerationSettings,
+    settings: GenerationSettings,
+}
+#[derive(Serialize, Debug)]
+struct Query {
+    stream: bool,
+    model: String,
+    messages: Vec<Message>,
+    grammar: Option<String>,
+    response_format: Option<ResponseFormat>,
+    temperature: f32,
+    n_predict: usize,
+}
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponseFormat {
+    JsonSchema { json_schema: JsonSchemaSpec },
 }
 #[derive(Serialize, Debug)]
-pub struct Query {
-    pub stream: bool,
-    pub model: String,
-    pub messages: Vec<Message>,
-    pub grammar: Option<String>,
-    pub temperature: f32,
-    pub n_predict: usize,
+struct JsonSchemaSpec {
+    name: &'static str,
+    schema: serde_json::Value,
+    strict: bool,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Debug)]
 #[serde(rename_all = "lowercase")]
-pub enum Role {
+enum Role {
     System,
     User,
     Assistant,
 }
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Message {
-    pub role: Role,
-    pub content: String,
-}
-#[derive(Deserialize, Debug)]
-pub struct Choice {
-    pub message: Message,
+impl From<&llm::Role> for Role {
+    fn from(r: &llm::Role) -> Self {
+        match r {
+            llm::Role::System => Role::System,
+            llm::Role::User => Role::User,
+            llm::Role::Assistant => Role::Assistant,
+        }
+    }
 }
-#[derive(Deserialize, Debug)]
-pub struct Response {
-    pub choices: Vec<Choice>,
-    pub timings: Timings,
+#[derive(Serialize, Debug)]
+struct Message {
+    role: Role,
+    content: String,
 }
-impl Response {
-    pub fn content(&self) -> anyhow::Result<&str> {
-        Ok(&self
-            .choices
-            .first()
-            .context("No responses returned")?
-            .message
-            .content)
+impl From<&llm::Message> for Message {
+    fn from(m: &llm::Message) -> Self {
+        Self {
+            role: (&m.role).into(),
+            content: m.content.clone(),
+        }
     }
 }
-#[derive(Deserialize, Debug)]
-#[allow(dead_code)]
-pub struct Timings {
-    predicted_ms: f32,
-    predicted_n: usize,
-    prompt_ms: f32,
-    prompt_n: usize,
-}
 #[derive(Deserialize, Default, Clone, Debug)]
-pub struct GenerationSettings {
-    pub n_ctx: usize,
+struct GenerationSettings {
+    n_ctx: usize,
 }
 #[derive(Deserialize, Debug)]
-pub struct Props {
-    pub default_generation_settings: GenerationSettings,
+struct Props {
+    default_generation_settings: GenerationSettings,
 }
-#[derive(Deserialize, Debug)]
-pub struct Models {
-    pub data: Vec<Model>,
-}
-#[derive(Deserialize, Debug)]
-pub struct Model {
-    pub id: String,
+
+/// Translate a backend-agnostic [`llm::OutputFormat`] into the `grammar` and
+/// `response_format` fields of a [`Query`].
+fn output_format(output: &llm::OutputFormat) -> (Option<String>, Option<ResponseFormat>) {
+    match output {
+        llm::OutputFormat::Grammar(g) => (Some(g.clone()), None),
+        llm::OutputFormat::Text => (None, None),
+        llm::OutputFormat::JsonSchema(schema) => (
+            None,
+            Some(ResponseFormat::JsonSchema {
+                json_schema: JsonSchemaSpec {
+                    name: "output",
+                    schema: schema.clone(),
+                    strict: true,
+                },
+            }),
+        ),
+    }
 }
+
 impl LlamaCpp {
     pub async fn new(url: &reqwest::Url) -> anyhow::Result<Self> {
         let mut s = Self {
@@ -92,15 +108,54 @@ impl LlamaCpp {
         debug!("Sending query");
         Ok(r.send().await?.error_for_status()?.json().await?)
     }
-    pub async fn props(&self) -> anyhow::Result<Props> {
+    async fn props(&self) -> anyhow::Result<Props> {
         self.send(self.client.get(self.url.join("props")?)).await
     }
-    pub async fn models(&self) -> anyhow::Result<Models> {
-        self.send(self.client.get(self.url.join("v1/models")?))
-            .await
+    /// Embed `text` via the `/embedding` endpoint.
+    pub async fn embedding(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        #[derive(Serialize)]
+        struct Query {
+            content: String,
+        }
+        #[derive(Deserialize)]
+        struct Response {
+            embedding: Vec<f32>,
+        }
+        let r: Vec<Response> = self
+            .send(self.client.post(self.url.join("embedding")?).json(&Query {
+                content: text.into(),
+            }))
+            .await?;
+        Ok(r.into_iter()
+            .next()
+            .context("No embedding returned")?
+            .embedding)
     }
-    #[allow(dead_code)]
-    pub async fn tokenize(&self, text: &str) -> anyhow::Result<Vec<usize>> {
+}
+#[async_trait::async_trait]
+impl LlmBackend for LlamaCpp {
+    async fn completions_stream(
+        &self,
+        query: &llm::CompletionRequest,
+    ) -> anyhow::Result<futures::stream::BoxStream<'static, anyhow::Result<String>>> {
+        let (grammar, response_format) = output_format(&query.output);
+        let response = self
+            .client
+            .post(self.url.join("v1/chat/completions")?)
+            .json(&Query {
+                stream: true,
+                model: query.model.clone(),
+                messages: query.messages.iter().map(Into::into).collect(),
+                grammar,
+                response_format,
+                temperature: query.temperature,
+                n_predict: query.n_predict,
+            })
+            .send()
+            .await?;
+        Ok(llm::parse_sse_stream(llm::check_status(response).await?))
+    }
+    async fn tokenize(&self, text: &str) -> anyhow::Result<Vec<usize>> {
         #[derive(Serialize)]
         struct Query {
             content: String,
@@ -116,16 +171,10 @@ impl LlamaCpp {
             .await?;
         Ok(r.tokens)
     }
-    #[instrument(skip_all)]
-    pub async fn completions(&self, query: &Query) -> anyhow::Result<Response> {
-        let r: Response = self
-            .send(
-                self.client
-                    .post(self.url.join("v1/chat/completions")?)
-                    .json(&query),
-            )
-            .await?;
-        debug!(?r.timings, "Received completion response");
-        Ok(r)
+    fn context_size(&self) -> usize {
+        self.settings.n_ctx
+    }
+    fn supports_json_schema(&self) -> bool {
+        true
     }
 }
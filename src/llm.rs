@@ -0,0 +1,167 @@
+//! Backend-agnostic types for talking to a chat-completion server.
+//!
+//! [`LlmBackend`] is implemented once per server flavour (see [`crate::llamacpp`]
+//! and [`crate::openai`]) so the rest of the crate does not need to care whether
+//! it is talking to a llama.cpp server, Ollama, vLLM, or a hosted API.
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+}
+
+/// Constraint placed on the completion output.
+#[derive(Debug, Clone)]
+pub enum OutputFormat {
+    /// No constraint.
+    Text,
+    /// A GBNF grammar, as understood by llama.cpp's `/v1/chat/completions` endpoint.
+    Grammar(String),
+    /// A JSON schema, as understood by OpenAI's `response_format: json_schema`.
+    JsonSchema(serde_json::Value),
+}
+
+#[derive(Debug)]
+pub struct CompletionRequest {
+    pub model: String,
+    pub messages: Vec<Message>,
+    pub temperature: f32,
+    pub n_predict: usize,
+    pub output: OutputFormat,
+}
+
+/// Returned by [`LlmBackend::completions_stream`] when the backend reports
+/// that the request did not fit in the model's context window, so the
+/// caller can re-truncate the prompt and retry.
+#[derive(Debug)]
+pub struct ContextOverflow;
+impl std::fmt::Display for ContextOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "request exceeds the model's context window")
+    }
+}
+impl std::error::Error for ContextOverflow {}
+
+/// A backend able to run chat completions, and optionally tokenize text.
+#[async_trait::async_trait]
+pub trait LlmBackend: Send + Sync {
+    /// Stream content deltas as they are generated rather than waiting for
+    /// the full response. Fails with [`ContextOverflow`] (downcastable via
+    /// [`anyhow::Error::downcast_ref`]) if the backend reports the request
+    /// did not fit in its context window.
+    async fn completions_stream(
+        &self,
+        query: &CompletionRequest,
+    ) -> anyhow::Result<futures::stream::BoxStream<'static, anyhow::Result<String>>>;
+    /// Tokenize `text`, returning one entry per token. Not all backends support this.
+    async fn tokenize(&self, text: &str) -> anyhow::Result<Vec<usize>>;
+    /// The context size (in tokens) of the selected model.
+    fn context_size(&self) -> usize;
+    /// Whether this backend accepts [`OutputFormat::JsonSchema`]. Callers should
+    /// fall back to a [`OutputFormat::Grammar`] for backends that only support
+    /// GBNF grammars.
+    fn supports_json_schema(&self) -> bool;
+}
+
+/// Check a completed request for a client or server-side error, turning one
+/// that looks like a context-overflow into [`ContextOverflow`] so callers can
+/// retry with a shorter prompt rather than failing outright.
+pub async fn check_status(response: reqwest::Response) -> anyhow::Result<reqwest::Response> {
+    let error = match response.error_for_status_ref() {
+        Ok(_) => return Ok(response),
+        Err(e) => e,
+    };
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    let looks_like_overflow = status.is_client_error() || status.is_server_error();
+    if looks_like_overflow && body.to_lowercase().contains("context") {
+        anyhow::bail!(ContextOverflow);
+    }
+    Err(anyhow::Error::new(error).context(body))
+}
+
+/// Turn an OpenAI-compatible `v1/chat/completions` SSE response (`data:
+/// {...}` lines, terminated by `data: [DONE]`) into a stream of content
+/// deltas.
+pub fn parse_sse_stream(
+    response: reqwest::Response,
+) -> futures::stream::BoxStream<'static, anyhow::Result<String>> {
+    #[derive(Deserialize, Debug)]
+    struct Delta {
+        content: Option<String>,
+    }
+    #[derive(Deserialize, Debug)]
+    struct Choice {
+        delta: Delta,
+    }
+    #[derive(Deserialize, Debug)]
+    struct Chunk {
+        choices: Vec<Choice>,
+    }
+    let bytes = response.bytes_stream();
+    Box::pin(futures::stream::unfold(
+        (bytes, String::new()),
+        |(mut bytes, mut buf)| async move {
+            loop {
+                if let Some(pos) = buf.find('\n') {
+                    let line: String = buf.drain(..=pos).collect();
+                    let line = line.trim();
+                    let data = match line.strip_prefix("data: ") {
+                        Some(data) => data,
+                        None => continue,
+                    };
+                    if data == "[DONE]" {
+                        return None;
+                    }
+                    let chunk: Chunk = match serde_json::from_str(data) {
+                        Ok(chunk) => chunk,
+                        Err(e) => return Some((Err(e.into()), (bytes, buf))),
+                    };
+                    let content = chunk.choices.into_iter().next().and_then(|c| c.delta.content);
+                    match content {
+                        Some(content) => return Some((Ok(content), (bytes, buf))),
+                        None => continue,
+                    }
+                }
+                match futures::StreamExt::next(&mut bytes).await {
+                    Some(Ok(chunk)) => buf.push_str(&String::from_utf8_lossy(&chunk)),
+                    Some(Err(e)) => return Some((Err(e.into()), (bytes, buf))),
+                    None => return None,
+                }
+            }
+        },
+    ))
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum BackendKind {
+    /// A llama.cpp server, using its `/props`, `/tokenize` and GBNF grammar support.
+    Llamacpp,
+    /// A generic OpenAI-compatible server (Ollama, vLLM, hosted APIs, ...).
+    #[value(name = "openai")]
+    OpenAi,
+}
+
+/// Instantiate the backend selected by `--backend`.
+pub async fn new(
+    kind: BackendKind,
+    url: &reqwest::Url,
+    context_size: Option<usize>,
+) -> anyhow::Result<std::sync::Arc<dyn LlmBackend>> {
+    use anyhow::Context;
+    Ok(match kind {
+        BackendKind::Llamacpp => std::sync::Arc::new(crate::llamacpp::LlamaCpp::new(url).await?),
+        BackendKind::OpenAi => std::sync::Arc::new(crate::openai::OpenAi::new(
+            url.clone(),
+            context_size.context("--context-size is required for the openai backend")?,
+        )),
+    })
+}
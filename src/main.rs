@@ -1,4 +1,12 @@
+mod chunking;
+mod config;
+mod embeddings;
 mod llamacpp;
+mod llm;
+mod openai;
+
+use std::collections::HashMap;
+use std::sync::Arc;
 
 use anyhow::Context;
 use clap::Parser;
@@ -7,18 +15,23 @@ use tracing::*;
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
-use unicode_truncate::UnicodeTruncateStr;
+
+use crate::llm::LlmBackend;
 
 mod paperless;
 
-/// The tag to identify documents to process. Set is as "inbox tag" in paperless.
-const TAG: &str = "llm-process";
-/// The name of the custom field containing the amount.
-const AMOUNT_FIELD: &str = "Amount";
 /// Temperature for sampling
 const TEMPERATURE: f32 = 0.0;
 /// Maximum response size
 const N_PREDICT: usize = 100;
+/// Token overlap between consecutive chunks, so that figures straddling a
+/// chunk boundary are not lost.
+const OVERLAP_TOKENS: usize = 64;
+/// Char-per-token ratio used when the backend does not support tokenization.
+const FALLBACK_CHARS_PER_TOKEN: f32 = 2.5;
+/// Appended to a task's prompt when reducing partial extractions from
+/// multiple chunks of the same document into a single answer.
+const REDUCE_PROMPT: &str = "You are given several partial extractions, independently performed on overlapping chunks of the same document, one JSON object per line, each prefixed with `Chunk N: `. Merge them into a single result following the same schema: prefer a present value over a missing (null) one, and pick the most representative value among differing candidates (e.g. for a title).";
 
 #[derive(Parser, Clone)]
 struct Flags {
@@ -26,8 +39,30 @@ struct Flags {
     paperless_url: reqwest::Url,
     #[clap(long)]
     paperless_token: String,
+    /// URL of the backend server (llama.cpp, Ollama, vLLM, a hosted API, ...).
+    #[clap(long)]
+    backend_url: reqwest::Url,
+    /// Which API the backend server speaks.
+    #[clap(long, value_enum, default_value = "llamacpp")]
+    backend: llm::BackendKind,
+    /// Model name to request from the backend.
+    #[clap(long)]
+    model: String,
+    /// Context size of the model, in tokens. Required for the `openai` backend,
+    /// which has no endpoint to discover it from.
+    #[clap(long)]
+    context_size: Option<usize>,
+    /// Path to the TOML config file describing the extraction tasks to run.
+    #[clap(long)]
+    config: std::path::PathBuf,
+    /// URL of a llama.cpp server exposing `/embedding`, used for tasks with a
+    /// `[classify]` section. Required if any task has one.
     #[clap(long)]
-    openai_url: reqwest::Url,
+    embeddings_url: Option<reqwest::Url>,
+    /// Minimum cosine similarity for an embedding-based classification to be
+    /// applied.
+    #[clap(long, default_value_t = 0.6)]
+    min_similarity: f32,
     #[clap(long)]
     apply: bool,
     #[clap(long)]
@@ -36,14 +71,146 @@ struct Flags {
     currency: String,
 }
 
+/// A [`config::Task`] together with the paperless ids its targets resolve to.
+struct TaskContext {
+    task: config::Task,
+    /// Id of the inbox tag identifying documents for this task.
+    tag_id: usize,
+    /// Custom field ids, keyed by [`config::Field::name`], for
+    /// [`config::FieldTarget::CustomField`] targets.
+    custom_field_ids: HashMap<String, usize>,
+    /// Tag ids, keyed by tag name, for [`config::FieldTarget::Tag`] targets.
+    tag_ids: HashMap<String, usize>,
+    /// Correspondent ids, keyed by name, for [`config::FieldTarget::Correspondent`]
+    /// targets.
+    correspondent_ids: HashMap<String, usize>,
+    /// Document type ids, keyed by name, for [`config::FieldTarget::DocumentType`]
+    /// targets.
+    document_type_ids: HashMap<String, usize>,
+    /// Embedding-based classifiers built from [`config::Task::classify`], if any.
+    classifiers: Option<TaskClassifiers>,
+}
+
+struct TaskClassifiers {
+    llamacpp: llamacpp::LlamaCpp,
+    correspondent: Option<embeddings::Classifier>,
+    tag: Option<embeddings::Classifier>,
+}
+
 #[derive(Clone)]
 struct Params {
     model: String,
     paperless: paperless::Paperless,
-    llamacpp: llamacpp::LlamaCpp,
+    backend: Arc<dyn llm::LlmBackend>,
     args: Flags,
-    field_id: usize,
-    tag_id: usize,
+    task: Arc<TaskContext>,
+}
+
+/// Run one extraction completion, streaming the response to surface
+/// progress on the current span, and parse its response against `fields`.
+/// Retries once, with `user_content` re-truncated to the backend's real
+/// token budget, if the backend reports the request did not fit in its
+/// context window.
+async fn extract(
+    params: &Params,
+    fields: &[config::Field],
+    system_prompt: String,
+    user_content: String,
+    output_format: llm::OutputFormat,
+) -> anyhow::Result<Vec<(config::Field, Option<config::FieldValue>)>> {
+    let content = match stream_completion(params, &system_prompt, &user_content, &output_format)
+        .await
+    {
+        Ok(content) => content,
+        Err(e) if e.downcast_ref::<llm::ContextOverflow>().is_some() => {
+            warn!("Request exceeded the model's context window, re-truncating and retrying once");
+            let (prompt_tokens, _) = count_tokens(&*params.backend, &system_prompt).await;
+            let (_, chars_per_token) = count_tokens(&*params.backend, &user_content).await;
+            let chars_per_token = chars_per_token.unwrap_or(FALLBACK_CHARS_PER_TOKEN);
+            let budget_tokens = params
+                .backend
+                .context_size()
+                .saturating_sub(prompt_tokens)
+                .saturating_sub(N_PREDICT);
+            let truncated = chunking::windows(&user_content, budget_tokens, 0, chars_per_token)
+                .into_iter()
+                .next()
+                .unwrap_or(user_content.as_str())
+                .to_string();
+            stream_completion(params, &system_prompt, &truncated, &output_format).await?
+        }
+        Err(e) => return Err(e),
+    };
+    config::parse_output(fields, &content).context("Response did not adhere to the structure")
+}
+
+/// Relax `fields` so every one of them is optional, for a map-reduce chunk's
+/// extraction: a field actually required in the config may simply be absent
+/// from a given chunk, and only the final reduced result must honor that
+/// requirement.
+fn relaxed_fields(fields: &[config::Field]) -> Vec<config::Field> {
+    fields
+        .iter()
+        .cloned()
+        .map(|mut field| {
+            field.optional = true;
+            field
+        })
+        .collect()
+}
+
+/// Run a streaming completion, updating the current span's progress message
+/// as content is generated rather than leaving it blocked and silent.
+async fn stream_completion(
+    params: &Params,
+    system_prompt: &str,
+    user_content: &str,
+    output_format: &llm::OutputFormat,
+) -> anyhow::Result<String> {
+    let mut stream = params
+        .backend
+        .completions_stream(&llm::CompletionRequest {
+            messages: vec![
+                llm::Message {
+                    role: llm::Role::System,
+                    content: system_prompt.into(),
+                },
+                llm::Message {
+                    role: llm::Role::User,
+                    content: user_content.into(),
+                },
+            ],
+            output: output_format.clone(),
+            model: params.model.clone(),
+            temperature: TEMPERATURE,
+            n_predict: N_PREDICT,
+        })
+        .await?;
+    let span = Span::current();
+    let mut content = String::new();
+    while let Some(delta) = stream.next().await {
+        content.push_str(&delta?);
+        span.pb_set_message(&format!("{} chars generated", content.len()));
+    }
+    Ok(content)
+}
+
+/// Real token count of `text` if the backend supports tokenization, otherwise
+/// an estimate based on [`FALLBACK_CHARS_PER_TOKEN`].
+async fn count_tokens(backend: &dyn llm::LlmBackend, text: &str) -> (usize, Option<f32>) {
+    match backend.tokenize(text).await {
+        Ok(tokens) => (
+            tokens.len(),
+            Some(text.len() as f32 / tokens.len().max(1) as f32),
+        ),
+        Err(e) => {
+            debug!("Tokenization unavailable, falling back to a char/token estimate: {e:?}");
+            (
+                (text.len() as f32 / FALLBACK_CHARS_PER_TOKEN).ceil() as usize,
+                None,
+            )
+        }
+    }
 }
 
 #[tracing::instrument(skip_all, fields(id=id))]
@@ -57,108 +224,210 @@ async fn process_document(id: usize, params: Params) -> anyhow::Result<()> {
         "Retrieved document"
     );
 
-    let n_ctx = params.llamacpp.settings.n_ctx;
-
-    let prompt = include_str!("../prompt.txt").replace("CURRENCY", &params.args.currency);
-
-    // Truncate the document to make sure prompt + doc + output fit in the available context.
-    // TODO: This is not great for the amount determination
-    // For now, this uses a simple heuristic based on a number of chars per token.
-    // TODO: Truncate more if the server refuses the request, or use the tokenizer endpoint first.
-    // let tokens = llamacpp.tokenize(&d.content).await?.len();
-    // info!(
-    //     "Tokens: {} actual / {} estimated / {} factor",
-    //     tokens,
-    //     d.content.len() as f32 / char_per_token,
-    //     d.content.len() as f32 / tokens as f32
-    // );
-    let char_per_token = 2.5;
-    let max_output_tokens = 50;
-    let max_doc_size =
-        (n_ctx as f32 * char_per_token - prompt.len() as f32 - max_output_tokens as f32).ceil()
-            as usize;
-    let content = if d.content.len() > max_doc_size {
-        warn!(
-            original = d.content.len(),
-            truncated = max_doc_size,
-            "Truncating long document"
-        );
-        d.content.unicode_truncate(max_doc_size).0
+    let fields = &params.task.task.fields;
+    // Fields used for a per-chunk map extraction: every field is optional,
+    // since a given chunk may simply not contain it. Only the final result
+    // (single-shot or after reducing) must honor the fields actually marked
+    // required in the config.
+    let chunk_fields = relaxed_fields(fields);
+    let system_prompt = params
+        .task
+        .task
+        .prompt
+        .replace("CURRENCY", &params.args.currency);
+    let output_format = if params.backend.supports_json_schema() {
+        llm::OutputFormat::JsonSchema(config::build_json_schema(fields))
+    } else {
+        llm::OutputFormat::Grammar(config::build_grammar(fields))
+    };
+    let chunk_output_format = if params.backend.supports_json_schema() {
+        llm::OutputFormat::JsonSchema(config::build_json_schema(&chunk_fields))
     } else {
-        &d.content
+        llm::OutputFormat::Grammar(config::build_grammar(&chunk_fields))
     };
 
+    // Reserve room for the system prompt and the model's response, and split
+    // the document into overlapping chunks if it doesn't fit in what remains.
+    let (prompt_tokens, chars_per_token) = count_tokens(&*params.backend, &system_prompt).await;
+    let (doc_tokens, doc_chars_per_token) = count_tokens(&*params.backend, &d.content).await;
+    let chars_per_token = chars_per_token
+        .or(doc_chars_per_token)
+        .unwrap_or(FALLBACK_CHARS_PER_TOKEN);
+    let budget_tokens = params
+        .backend
+        .context_size()
+        .saturating_sub(prompt_tokens)
+        .saturating_sub(N_PREDICT);
+
     info!("Sending query to LLM");
-    let r = params
-        .llamacpp
-        .completions(&llamacpp::Query {
-            messages: vec![
-                llamacpp::Message {
-                    role: llamacpp::Role::System,
-                    content: prompt,
-                },
-                llamacpp::Message {
-                    role: llamacpp::Role::User,
-                    content: content.into(),
-                },
-            ],
-            grammar: Some(include_str!("../grammar.gbnf").into()),
-            stream: false,
-            model: params.model,
-            temperature: TEMPERATURE,
-            n_predict: N_PREDICT,
-        })
-        .await?;
-    // Parse the structured output
-    #[derive(Debug)]
-    struct Output {
-        title: String,
-        amount: Option<f32>,
-    }
-    impl std::str::FromStr for Output {
-        type Err = anyhow::Error;
-        fn from_str(s: &str) -> Result<Self, Self::Err> {
-            let lines: Vec<&str> = s.lines().collect();
-            anyhow::ensure!(lines.len() == 2, "Incorrect number of lines");
-            Ok(Self {
-                title: lines[0].into(),
-                amount: if lines[1] == "-" {
-                    None
-                } else {
-                    Some(lines[1].parse()?)
-                },
+    let output = if doc_tokens <= budget_tokens {
+        extract(
+            &params,
+            fields,
+            system_prompt,
+            d.content.clone(),
+            output_format,
+        )
+        .await?
+    } else {
+        let windows = chunking::windows(&d.content, budget_tokens, OVERLAP_TOKENS, chars_per_token);
+        info!(
+            tokens = doc_tokens,
+            budget = budget_tokens,
+            chunks = windows.len(),
+            "Document exceeds context budget, processing in chunks"
+        );
+        let chunk_outputs: Vec<_> = futures::stream::iter(windows.into_iter().enumerate())
+            .map(|(i, chunk)| {
+                let system_prompt = system_prompt.clone();
+                let chunk_output_format = chunk_output_format.clone();
+                let chunk_fields = &chunk_fields;
+                let params = &params;
+                async move {
+                    extract(params, chunk_fields, system_prompt, chunk.into(), chunk_output_format)
+                        .await
+                        .inspect_err(|e| warn!("Error processing chunk {}: {:?}", i, e))
+                        .ok()
+                }
             })
+            .buffer_unordered(10)
+            .filter_map(futures::future::ready)
+            .collect()
+            .await;
+        anyhow::ensure!(!chunk_outputs.is_empty(), "All chunks failed extraction");
+
+        let partials = chunk_outputs
+            .iter()
+            .enumerate()
+            .map(|(i, output)| format!("Chunk {}: {}", i + 1, config::to_json(output)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        info!(chunks = chunk_outputs.len(), "Reducing partial extractions");
+        extract(
+            &params,
+            fields,
+            format!("{system_prompt}\n\n{REDUCE_PROMPT}"),
+            partials,
+            output_format,
+        )
+        .await?
+    };
+    info!(?output, "Document processed by LLM");
+
+    let mut d = d;
+    let mut title = None;
+    let mut correspondent = d.correspondent;
+    let mut document_type = d.document_type;
+    for (field, value) in output {
+        let value = match value {
+            Some(value) => value,
+            None => continue,
+        };
+        match (&field.target, value) {
+            (config::FieldTarget::Title, config::FieldValue::String(s)) => title = Some(s),
+            (config::FieldTarget::CustomField(_), value) => {
+                let field_id = params.task.custom_field_ids[&field.name];
+                let value = match value {
+                    config::FieldValue::String(s) => s.into(),
+                    config::FieldValue::Number(n) if field.currency => {
+                        format!("{}{:.2}", params.args.currency, n).into()
+                    }
+                    config::FieldValue::Number(n) => serde_json::json!(n),
+                    config::FieldValue::Date(d) => d.format("%Y-%m-%d").to_string().into(),
+                };
+                d.custom_fields = d
+                    .custom_fields
+                    .into_iter()
+                    .filter(|f| f.field != field_id)
+                    .chain(std::iter::once(paperless::CustomFieldValue {
+                        field: field_id,
+                        value,
+                    }))
+                    .collect();
+            }
+            (config::FieldTarget::Tag, config::FieldValue::String(s)) => {
+                let tag_id = *params
+                    .task
+                    .tag_ids
+                    .get(&s)
+                    .with_context(|| format!("Field '{}': no tag named '{}'", field.name, s))?;
+                if !d.tags.contains(&tag_id) {
+                    d.tags.push(tag_id);
+                }
+            }
+            (config::FieldTarget::Correspondent, config::FieldValue::String(s)) => {
+                correspondent = Some(*params.task.correspondent_ids.get(&s).with_context(
+                    || format!("Field '{}': no correspondent named '{}'", field.name, s),
+                )?);
+            }
+            (config::FieldTarget::DocumentType, config::FieldValue::String(s)) => {
+                document_type = Some(*params.task.document_type_ids.get(&s).with_context(
+                    || format!("Field '{}': no document type named '{}'", field.name, s),
+                )?);
+            }
+            (target, value) => anyhow::bail!(
+                "Field '{}': value {:?} does not match target {:?}",
+                field.name,
+                value,
+                target
+            ),
         }
     }
-    let output: Output = r
-        .content()?
-        .parse()
-        .context("Response did not adhere to the structure")?;
-    info!(?output, ?r.timings, "Document processed by LLM");
-    if d.title != output.title {
-        info!("'{}'", prettydiff::diff_words(&d.title, &output.title));
+    if let Some(title) = &title {
+        if d.title != *title {
+            info!("'{}'", prettydiff::diff_words(&d.title, title));
+        }
+    }
+
+    if let Some(classifiers) = &params.task.classifiers {
+        let (doc_tokens, chars_per_token) = count_tokens(&classifiers.llamacpp, &d.content).await;
+        let embed_content = if doc_tokens <= classifiers.llamacpp.context_size() {
+            d.content.as_str()
+        } else {
+            chunking::windows(
+                &d.content,
+                classifiers.llamacpp.context_size(),
+                0,
+                chars_per_token.unwrap_or(FALLBACK_CHARS_PER_TOKEN),
+            )
+            .into_iter()
+            .next()
+            .unwrap_or(d.content.as_str())
+        };
+        if let Some(classifier) = &classifiers.correspondent {
+            if let Some((name, id)) = classifier
+                .classify(&classifiers.llamacpp, embed_content, params.args.min_similarity)
+                .await?
+            {
+                info!(correspondent = name, "Assigning correspondent via embeddings");
+                correspondent = Some(id);
+            }
+        }
+        if let Some(classifier) = &classifiers.tag {
+            if let Some((name, id)) = classifier
+                .classify(&classifiers.llamacpp, embed_content, params.args.min_similarity)
+                .await?
+            {
+                info!(tag = name, "Assigning tag via embeddings");
+                if !d.tags.contains(&id) {
+                    d.tags.push(id);
+                }
+            }
+        }
     }
+
     if params.args.apply {
-        let mut d = d;
         info!("Updating document");
-        if let Some(amount) = output.amount {
-            d.custom_fields = d
-                .custom_fields
-                .into_iter()
-                .filter(|f| f.field != params.field_id)
-                .chain(std::iter::once(paperless::CustomFieldValue {
-                    field: params.field_id,
-                    value: format!("{}{:.2}", params.args.currency, amount).into(),
-                }))
-                .collect();
-        }
-        d.tags.retain(|t| *t != params.tag_id);
-        let patch = serde_json::json!({"title": output.title, "tags": d.tags, "custom_fields": d.custom_fields });
+        d.tags.retain(|t| *t != params.task.tag_id);
+        let patch = serde_json::json!({
+            "title": title.unwrap_or(d.title),
+            "tags": d.tags,
+            "custom_fields": d.custom_fields,
+            "correspondent": correspondent,
+            "document_type": document_type,
+        });
         debug!(?patch, "Computed patch");
-        params.paperless.patch_document(
-            id,
-            serde_json::json!({"title": output.title, "tags": d.tags, "custom_fields": d.custom_fields }),
-        ).await?;
+        params.paperless.patch_document(id, patch).await?;
     }
     Ok(())
 }
@@ -199,69 +468,176 @@ async fn main_impl(args: Flags) -> anyhow::Result<()> {
     }
     warn_apply(&args);
 
+    info!(path=?args.config, "Loading config");
+    let config = config::Config::load(&args.config)?;
+
+    info!("Connecting to backend");
+    let backend = llm::new(args.backend, &args.backend_url, args.context_size).await?;
+    info!(
+        model = args.model,
+        ctx = backend.context_size(),
+        "Connected to backend"
+    );
+
     info!("Retrieving documents from paperless");
     let paperless = paperless::Paperless::new(args.paperless_url.clone(), &args.paperless_token);
-    let field_id = *paperless
-        .custom_fields()
-        .await?
-        .get(AMOUNT_FIELD)
-        .context("Failed to find amount custom field")?;
-    let tag_id = *paperless
-        .tags()
-        .await?
-        .get(TAG)
-        .context("Failed to find tag")?;
-    let mut d: Vec<usize> = if args.process_all {
-        paperless.documents(&[]).await?
+    let tags = paperless.tags().await?;
+    let custom_fields = paperless.custom_fields().await?;
+    let correspondents = paperless.correspondents().await?;
+    let document_types = paperless.document_types().await?;
+
+    let embeddings_llamacpp = if config.tasks.iter().any(|t| t.classify.is_some()) {
+        Some(
+            llamacpp::LlamaCpp::new(
+                args.embeddings_url
+                    .as_ref()
+                    .context("--embeddings-url is required for tasks with a [classify] section")?,
+            )
+            .await?,
+        )
     } else {
-        paperless.documents_with_tag(TAG).await?
+        None
     };
-    d.sort();
-    info!("Found {} documents (with tag {}) to process", d.len(), TAG);
 
-    info!("Selecting model");
-    let llamacpp = llamacpp::LlamaCpp::new(&args.openai_url).await?;
-    let models = llamacpp
-        .models()
-        .await
-        .context("Failed to retrieve models")?;
-    let model = &models.data.first().context("No model found")?.id;
-    info!(model, ctx = llamacpp.settings.n_ctx, "Selected model");
-
-    let span = info_span!("process");
-    span.pb_set_style(&indicatif::ProgressStyle::with_template(
-        "{wide_bar} {pos}/{len} ({percent}%) ETA {eta}",
-    )?);
-    span.pb_set_length(d.len() as u64);
-    let _span = span.enter();
-    info!("Processing all {} documents", d.len());
-
-    let params = Params {
-        model: model.into(),
-        paperless,
-        llamacpp,
-        args: args.clone(),
-        field_id,
-        tag_id,
-    };
-    let failed = futures::stream::iter(d)
-        .map(|d| {
-            let params = params.clone();
-            async move {
-                let r = process_document(d, params)
-                    .await
-                    .inspect_err(|e| error!("Error processing document {}: {:?}", d, e))
-                    .err()
-                    .map(|_| d);
-                Span::current().pb_inc(1);
-                r
+    let mut failed = Vec::new();
+    for task in &config.tasks {
+        let tag_id = *tags
+            .get(&task.tag)
+            .with_context(|| format!("Failed to find tag '{}'", task.tag))?;
+        let mut custom_field_ids = HashMap::new();
+        let mut tag_ids = HashMap::new();
+        let mut correspondent_ids = HashMap::new();
+        let mut document_type_ids = HashMap::new();
+        for field in &task.fields {
+            match &field.target {
+                config::FieldTarget::CustomField(name) => {
+                    let field_id = *custom_fields
+                        .get(name)
+                        .with_context(|| format!("Failed to find custom field '{}'", name))?;
+                    custom_field_ids.insert(field.name.clone(), field_id);
+                }
+                config::FieldTarget::Tag => {
+                    if let config::FieldType::Enum(values) = &field.r#type {
+                        for value in values {
+                            let id = *tags
+                                .get(value)
+                                .with_context(|| format!("Failed to find tag '{}'", value))?;
+                            tag_ids.insert(value.clone(), id);
+                        }
+                    }
+                }
+                config::FieldTarget::Correspondent => {
+                    if let config::FieldType::Enum(values) = &field.r#type {
+                        for value in values {
+                            let id = *correspondents.get(value).with_context(|| {
+                                format!("Failed to find correspondent '{}'", value)
+                            })?;
+                            correspondent_ids.insert(value.clone(), id);
+                        }
+                    }
+                }
+                config::FieldTarget::DocumentType => {
+                    if let config::FieldType::Enum(values) = &field.r#type {
+                        for value in values {
+                            let id = *document_types.get(value).with_context(|| {
+                                format!("Failed to find document type '{}'", value)
+                            })?;
+                            document_type_ids.insert(value.clone(), id);
+                        }
+                    }
+                }
+                config::FieldTarget::Title => {}
             }
-        })
-        .buffer_unordered(10)
-        .filter_map(futures::future::ready)
-        .collect::<Vec<usize>>()
-        .await;
-    drop(_span);
+        }
+
+        let classifiers = match &task.classify {
+            Some(classify) => {
+                let llamacpp = embeddings_llamacpp
+                    .clone()
+                    .context("--embeddings-url is required for tasks with a [classify] section")?;
+                let correspondent = if classify.correspondent {
+                    Some(embeddings::Classifier::build(&llamacpp, &correspondents).await?)
+                } else {
+                    None
+                };
+                let tag = if !classify.tags.is_empty() {
+                    let candidates = classify
+                        .tags
+                        .iter()
+                        .map(|name| {
+                            let id = *tags
+                                .get(name)
+                                .with_context(|| format!("Failed to find tag '{}'", name))?;
+                            Ok((name.clone(), id))
+                        })
+                        .collect::<anyhow::Result<HashMap<_, _>>>()?;
+                    Some(embeddings::Classifier::build(&llamacpp, &candidates).await?)
+                } else {
+                    None
+                };
+                Some(TaskClassifiers {
+                    llamacpp,
+                    correspondent,
+                    tag,
+                })
+            }
+            None => None,
+        };
+
+        let mut d: Vec<usize> = if args.process_all {
+            paperless.documents(&[]).await?
+        } else {
+            paperless.documents_with_tag(&task.tag).await?
+        };
+        d.sort();
+        info!(
+            "Found {} documents (with tag {}) to process",
+            d.len(),
+            task.tag
+        );
+
+        let span = info_span!("process", tag = task.tag);
+        span.pb_set_style(&indicatif::ProgressStyle::with_template(
+            "{wide_bar} {pos}/{len} ({percent}%) ETA {eta}",
+        )?);
+        span.pb_set_length(d.len() as u64);
+        let _span = span.enter();
+
+        let params = Params {
+            model: args.model.clone(),
+            paperless: paperless.clone(),
+            backend: backend.clone(),
+            args: args.clone(),
+            task: Arc::new(TaskContext {
+                task: task.clone(),
+                tag_id,
+                custom_field_ids,
+                tag_ids,
+                correspondent_ids,
+                document_type_ids,
+                classifiers,
+            }),
+        };
+        failed.extend(
+            futures::stream::iter(d)
+                .map(|d| {
+                    let params = params.clone();
+                    async move {
+                        let r = process_document(d, params)
+                            .await
+                            .inspect_err(|e| error!("Error processing document {}: {:?}", d, e))
+                            .err()
+                            .map(|_| d);
+                        Span::current().pb_inc(1);
+                        r
+                    }
+                })
+                .buffer_unordered(10)
+                .filter_map(futures::future::ready)
+                .collect::<Vec<usize>>()
+                .await,
+        );
+    }
     info!(elapsed=?start.elapsed(), "Done processing everything");
     if !failed.is_empty() {
         error!(?failed, "{} documents failed processing", failed.len());
@@ -0,0 +1,121 @@
+//! Generic client for OpenAI-compatible chat completion servers (Ollama, vLLM,
+//! hosted providers, ...).
+//!
+//! Unlike [`crate::llamacpp::LlamaCpp`], this backend has no `/props` endpoint to
+//! discover the context size from, and no GBNF grammar support, so both are the
+//! caller's responsibility (see `--context-size`).
+use serde::Serialize;
+use tracing::*;
+
+use crate::llm::{self, LlmBackend};
+
+#[derive(Clone)]
+pub struct OpenAi {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    context_size: usize,
+}
+#[derive(Serialize, Debug)]
+struct Query {
+    stream: bool,
+    model: String,
+    messages: Vec<Message>,
+    response_format: Option<ResponseFormat>,
+    temperature: f32,
+    max_tokens: usize,
+}
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponseFormat {
+    JsonSchema { json_schema: JsonSchemaSpec },
+}
+#[derive(Serialize, Debug)]
+struct JsonSchemaSpec {
+    name: &'static str,
+    schema: serde_json::Value,
+    strict: bool,
+}
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Role {
+    System,
+    User,
+    Assistant,
+}
+impl From<&llm::Role> for Role {
+    fn from(r: &llm::Role) -> Self {
+        match r {
+            llm::Role::System => Role::System,
+            llm::Role::User => Role::User,
+            llm::Role::Assistant => Role::Assistant,
+        }
+    }
+}
+#[derive(Serialize, Debug)]
+struct Message {
+    role: Role,
+    content: String,
+}
+impl From<&llm::Message> for Message {
+    fn from(m: &llm::Message) -> Self {
+        Self {
+            role: (&m.role).into(),
+            content: m.content.clone(),
+        }
+    }
+}
+impl OpenAi {
+    pub fn new(url: reqwest::Url, context_size: usize) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::new(),
+            context_size,
+        }
+    }
+}
+#[async_trait::async_trait]
+impl LlmBackend for OpenAi {
+    async fn completions_stream(
+        &self,
+        query: &llm::CompletionRequest,
+    ) -> anyhow::Result<futures::stream::BoxStream<'static, anyhow::Result<String>>> {
+        anyhow::ensure!(
+            !matches!(query.output, llm::OutputFormat::Grammar(_)),
+            "the OpenAI-compatible backend does not support GBNF grammars"
+        );
+        let response_format = match &query.output {
+            llm::OutputFormat::JsonSchema(schema) => Some(ResponseFormat::JsonSchema {
+                json_schema: JsonSchemaSpec {
+                    name: "output",
+                    schema: schema.clone(),
+                    strict: true,
+                },
+            }),
+            llm::OutputFormat::Text | llm::OutputFormat::Grammar(_) => None,
+        };
+        debug!("Sending streaming query");
+        let response = self
+            .client
+            .post(self.url.join("v1/chat/completions")?)
+            .json(&Query {
+                stream: true,
+                model: query.model.clone(),
+                messages: query.messages.iter().map(Into::into).collect(),
+                response_format,
+                temperature: query.temperature,
+                max_tokens: query.n_predict,
+            })
+            .send()
+            .await?;
+        Ok(llm::parse_sse_stream(llm::check_status(response).await?))
+    }
+    async fn tokenize(&self, _text: &str) -> anyhow::Result<Vec<usize>> {
+        anyhow::bail!("the OpenAI-compatible backend does not expose a tokenize endpoint")
+    }
+    fn context_size(&self) -> usize {
+        self.context_size
+    }
+    fn supports_json_schema(&self) -> bool {
+        true
+    }
+}
@@ -25,6 +25,8 @@ pub struct DocumentResponse {
     pub title: String,
     pub custom_fields: Vec<CustomFieldValue>,
     pub tags: Vec<usize>,
+    pub correspondent: Option<usize>,
+    pub document_type: Option<usize>,
 }
 #[derive(Debug, Deserialize)]
 struct Results<T> {
@@ -63,6 +65,12 @@ impl Paperless {
     pub async fn tags(&self) -> anyhow::Result<HashMap<String, usize>> {
         self.id_name("tags/").await
     }
+    pub async fn correspondents(&self) -> anyhow::Result<HashMap<String, usize>> {
+        self.id_name("correspondents/").await
+    }
+    pub async fn document_types(&self) -> anyhow::Result<HashMap<String, usize>> {
+        self.id_name("document_types/").await
+    }
     async fn id_name(&self, method: &str) -> anyhow::Result<HashMap<String, usize>> {
         #[derive(Debug, Deserialize)]
         pub struct IdName {